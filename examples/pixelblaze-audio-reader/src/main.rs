@@ -4,17 +4,28 @@ extern crate text_io;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::Parser;
 use cpal::{BufferSize, Device, InputCallbackInfo, SampleFormat, SampleRate};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rubato::Resampler;
 use spectrum_analyzer::{FrequencyLimit, samples_fft_to_spectrum};
 use spectrum_analyzer::scaling::divide_by_N;
 use spectrum_analyzer::windows::hann_window;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal};
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::conv::IntoSample;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::sample::Sample;
 
-use pixelblaze_rs::sensor::{AudioData, SensorClient};
+use pixelblaze_rs::sensor::{AudioData, BeatDetector, BucketScale, FrequencyBucketer, SensorClient};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -25,9 +36,82 @@ struct Cli {
     #[arg(short = 'r', long, default_value = "48000")]
     sample_rate_hz: u32,
 
+    /// Multiplier applied to the rolling average spectral flux to form the beat-detection
+    /// threshold. Higher values require a bigger jump in energy to register as a beat.
+    #[arg(long, default_value = "1.5")]
+    beat_sensitivity: f32,
+
+    /// Decode and analyze an audio file (mp3/wav/flac/ogg) instead of opening a live input
+    /// device. Playback is paced to real time so patterns animate at their natural speed.
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// When used with --file, repeat the file forever instead of exiting at EOF.
+    #[arg(long)]
+    r#loop: bool,
+
+    /// Caps how often frames are actually sent to targets, regardless of how fast audio
+    /// frames arrive. Unset by default (uncapped).
+    #[arg(long)]
+    max_fps: Option<f64>,
+
+    /// How the spectrum between `--low-freq-hz` and `--high-freq-hz` is spread across the 32
+    /// frequency buckets.
+    /// `log`/`mel` match the spectral balance of Pixelblaze's real SB10 sensor board better
+    /// than the naive equal-width `linear` chunking.
+    #[arg(long, value_enum, default_value_t = ScaleArg::Log)]
+    scale: ScaleArg,
+
+    /// Low edge in Hz of the frequency range spread across the 32 buckets.
+    #[arg(long, default_value = "30.0")]
+    low_freq_hz: f32,
+
+    /// High edge in Hz of the frequency range spread across the 32 buckets.
+    #[arg(long, default_value = "10000.0")]
+    high_freq_hz: f32,
+
+    /// Synthesize audio directly instead of opening a mic/loopback device, for validating a
+    /// Pixelblaze setup and the bucketing/beat detection against known stimuli.
+    #[arg(long, value_enum)]
+    generate: Option<GenerateArg>,
+
+    /// Frequency in Hz for `--generate sine`.
+    #[arg(long, default_value = "440.0")]
+    gen_freq_hz: f32,
+
+    /// Duration in seconds of one cycle of `--generate sweep`, covering 20Hz-20kHz
+    /// logarithmically before repeating.
+    #[arg(long, default_value = "10.0")]
+    gen_sweep_secs: f32,
+
     targets: Vec<SocketAddr>,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum GenerateArg {
+    Sine,
+    Sweep,
+    Pink,
+    Silence,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ScaleArg {
+    Linear,
+    Log,
+    Mel,
+}
+
+impl From<ScaleArg> for BucketScale {
+    fn from(scale: ScaleArg) -> BucketScale {
+        match scale {
+            ScaleArg::Linear => BucketScale::Linear,
+            ScaleArg::Log => BucketScale::Log,
+            ScaleArg::Mel => BucketScale::Mel,
+        }
+    }
+}
+
 ///! This is a simple utility for accepting audio input, performing frequency analysis, then
 ///! shipping the analysis off to a pixelblaze. It's tested on OSX, if you get it working on
 ///! other platforms please let me know! It has not been tested with multiple targets (yet).
@@ -44,6 +128,9 @@ struct Cli {
 ///!
 ///! Windows/Linux: Contributions welcome
 ///!
+///! Alternatively, pass `--file <path>` to decode and analyze an audio file directly, which
+///! needs no mic or loopback device and is handy for testing patterns against known material.
+///!
 ///! Running this example:
 ///!  1. Set up the loopback audio device as outlined above (optional)
 ///!  2. Power on your Pixelblaze and select a sound-reactive pattern
@@ -63,6 +150,38 @@ struct Cli {
 ///! If you run into issues or have tuning suggestions, please contact the author.
 fn main() {
     let cli = Cli::parse();
+
+    let mut client = SensorClient::new(89).expect("Couldn't bind sensor client socket");
+    cli.targets.iter().for_each(|target| client.add_target(target.clone()));
+    if let Some(max_fps) = cli.max_fps {
+        client.set_min_frame_interval(Some(Duration::from_secs_f64(1.0 / max_fps)));
+    }
+    let processor = FrameProcessor::new(
+        client,
+        cli.beat_sensitivity,
+        cli.sample_rate_hz,
+        cli.scale.into(),
+        cli.low_freq_hz,
+        cli.high_freq_hz,
+    );
+
+    if let Some(mode) = cli.generate {
+        run_generate_source(
+            mode,
+            cli.frame_samples as usize,
+            cli.sample_rate_hz,
+            cli.gen_freq_hz,
+            cli.gen_sweep_secs,
+            processor,
+        );
+        return;
+    }
+
+    if let Some(path) = cli.file.clone() {
+        run_file_source(&path, cli.r#loop, cli.frame_samples as usize, cli.sample_rate_hz, processor);
+        return;
+    }
+
     let host = cpal::default_host();
 
     let devices: Vec<Device> = host.input_devices()
@@ -104,13 +223,70 @@ fn main() {
         .nth(0)
         .expect("No fitting input configuration found");
 
-    let mut client = SensorClient::new(89);
-    cli.targets.iter().for_each(|target| client.add_target(target.clone()));
-
-    let mut frame_count: u64 = 0;
-    let mut last_frame = Instant::now();
-    let mut padding_buf = Vec::new();
+    let mut processor = processor;
     let to_spectrum_fn = move |audio: &[f32], _: &InputCallbackInfo| {
+        processor.process(audio);
+    };
+
+    let mut config = config.config();
+    config.buffer_size = BufferSize::Fixed(cli.frame_samples);
+    println!("Found config: {:?}", config);
+
+    let stream = input_device.build_input_stream(
+        &config,
+        to_spectrum_fn,
+        |err| eprintln!("an error occurred on stream: {}", err),
+        None,
+    ).expect("Build input stream failed");
+    stream.play().expect("Play failed");
+
+    loop {
+        thread::sleep(Duration::from_secs(1))
+    }
+}
+
+/// Carries the per-frame spectral analysis state (the FFT -> bucketing -> beat detection ->
+/// `SensorClient::send_frame` pipeline) so it can be driven equally by a live cpal callback or
+/// a paced file decode loop.
+struct FrameProcessor {
+    client: SensorClient,
+    beat_detector: BeatDetector,
+    sample_rate_hz: u32,
+    scale: BucketScale,
+    low_freq_hz: f32,
+    high_freq_hz: f32,
+    bucketer: Option<FrequencyBucketer>,
+    bucketer_bin_count: usize,
+    frame_count: u64,
+    last_frame: Instant,
+    padding_buf: Vec<f32>,
+}
+
+impl FrameProcessor {
+    fn new(
+        client: SensorClient,
+        beat_sensitivity: f32,
+        sample_rate_hz: u32,
+        scale: BucketScale,
+        low_freq_hz: f32,
+        high_freq_hz: f32,
+    ) -> FrameProcessor {
+        FrameProcessor {
+            client,
+            beat_detector: BeatDetector::new(beat_sensitivity),
+            sample_rate_hz,
+            scale,
+            low_freq_hz,
+            high_freq_hz,
+            bucketer: None,
+            bucketer_bin_count: 0,
+            frame_count: 0,
+            last_frame: Instant::now(),
+            padding_buf: Vec::new(),
+        }
+    }
+
+    fn process(&mut self, audio: &[f32]) {
         let audio = if audio.len().count_ones() == 1 {
             audio
         } else {
@@ -118,39 +294,51 @@ fn main() {
             // Might as well pad instead of panicking
             let new_len = audio.len().next_power_of_two();
             let mut padding = vec![0.0_f32; new_len - audio.len()];
-            padding_buf.clear();
-            padding_buf.extend_from_slice(audio);
-            padding_buf.append(&mut padding);
-            &padding_buf[..]
+            self.padding_buf.clear();
+            self.padding_buf.extend_from_slice(audio);
+            self.padding_buf.append(&mut padding);
+            &self.padding_buf[..]
         };
 
         let hann_window = hann_window(audio);
         let latest_spectrum = samples_fft_to_spectrum(
             &hann_window,
-            cli.sample_rate_hz,
+            self.sample_rate_hz,
             FrequencyLimit::All,
             Some(&divide_by_N),
         ).unwrap();
 
         let energy_avg = latest_spectrum.average();
         let (max_freq, max_freq_magnitude) = latest_spectrum.max();
-        let trimmed: Vec<f32> = latest_spectrum.data().iter()
-            .take_while(|(freq, _)| freq.val() < 10_000f32)
+
+        let magnitudes: Vec<f32> = latest_spectrum.data().iter()
             .map(|(_, freq_val)| freq_val.val())
             .collect();
-        let bucketed: Vec<u16> = trimmed.chunks(trimmed.len() / 32)
-            .take(32)
-            .map(|c| c.iter().sum())
-            .map(|flt: f32| (flt.clamp(0.0, 1.0).to_scaled_u16()))
+        let beat = self.beat_detector.process(&magnitudes);
+
+        if self.bucketer.is_none() || self.bucketer_bin_count != magnitudes.len() {
+            self.bucketer = Some(FrequencyBucketer::new(
+                self.scale,
+                self.low_freq_hz,
+                self.high_freq_hz,
+                32,
+                magnitudes.len(),
+                self.sample_rate_hz,
+            ));
+            self.bucketer_bin_count = magnitudes.len();
+        }
+        let bucketed: Vec<u16> = self.bucketer.as_ref().unwrap().bucket(&magnitudes).iter()
+            .map(|flt: &f32| flt.clamp(0.0, 1.0).to_scaled_u16())
             .collect();
+        let bucketed: [u16; 32] = bucketed.try_into().expect("bucket() always yields 32 buckets");
 
-        frame_count += 1;
-        let frame_delay = Instant::now().duration_since(last_frame);
-        last_frame = Instant::now();
-        if frame_count % 50 == 0 {
+        self.frame_count += 1;
+        let frame_delay = Instant::now().duration_since(self.last_frame);
+        self.last_frame = Instant::now();
+        if self.frame_count % 50 == 0 {
             println!(
                 "Sent {} frames. ({}ms/frame) frame size = {}. spectrum[1].freq = {}Hz, spectrum[{}].freq={}Hz. bucketed[0]={}, bucketed[{}] = {}",
-                frame_count,
+                self.frame_count,
                 frame_delay.as_millis(),
                 audio.len(),
                 latest_spectrum.data()[1].0.val(),
@@ -162,35 +350,287 @@ fn main() {
             );
         }
 
-        let audio = AudioData {
+        let audio_data = AudioData {
             freq_buckets: bucketed,
             energy_avg: energy_avg.val().to_scaled_u16(),
             max_freq_magnitude: max_freq_magnitude.val().to_scaled_u16(),
             max_freq: max_freq.val().to_scaled_u16(),
         };
 
-        if let Err(err) = client.send_frame(&audio, &[0; 3], 0, &[0; 5]) {
-            eprintln!("Failed to send frame: {:?}", err)
+        let analog = [beat.to_scaled_u16(), 0, 0, 0, 0];
+        match self.client.send_frame(&audio_data, &[0; 3], 0, &analog) {
+            Ok(summary) => for outcome in summary.outcomes {
+                if let Err(err) = outcome.result {
+                    eprintln!("Failed to send frame to {}: {:?}", outcome.target, err)
+                }
+            },
+            Err(err) => eprintln!("Failed to send frame: {:?}", err),
         }
-    };
+    }
+}
 
-    let mut config = config.config();
-    config.buffer_size = BufferSize::Fixed(cli.frame_samples);
-    println!("Found config: {:?}", config);
+/// Synthesizes mono `f32` audio directly, no mic or loopback device needed. Useful for
+/// validating the bucketing and beat-detection pipelines against known stimuli.
+struct Siggen {
+    mode: GenerateArg,
+    sample_rate_hz: u32,
+    freq_hz: f32,
+    sweep_secs: f32,
+    phase: f32,
+    elapsed_samples: u64,
+    pink_rows: [f32; 16],
+    pink_counter: u32,
+    rng: Xorshift32,
+}
 
-    let stream = input_device.build_input_stream(
-        &config,
-        to_spectrum_fn,
-        |err| eprintln!("an error occurred on stream: {}", err),
-        None,
-    ).expect("Build input stream failed");
-    stream.play().expect("Play failed");
+impl Siggen {
+    fn new(mode: GenerateArg, sample_rate_hz: u32, freq_hz: f32, sweep_secs: f32) -> Siggen {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|dur| dur.subsec_nanos())
+            .unwrap_or(1)
+            .max(1);
+
+        Siggen {
+            mode,
+            sample_rate_hz,
+            freq_hz,
+            sweep_secs,
+            phase: 0.0,
+            elapsed_samples: 0,
+            pink_rows: [0.0; 16],
+            pink_counter: 0,
+            rng: Xorshift32::new(seed),
+        }
+    }
+
+    fn next_hop(&mut self, frame_samples: usize) -> Vec<f32> {
+        (0..frame_samples).map(|_| self.next_sample()).collect()
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let sample = match self.mode {
+            GenerateArg::Silence => 0.0,
+            GenerateArg::Sine => self.next_sine_sample(self.freq_hz),
+            GenerateArg::Sweep => {
+                let period_samples = (self.sweep_secs * self.sample_rate_hz as f32).max(1.0) as u64;
+                let progress = (self.elapsed_samples % period_samples) as f32 / period_samples as f32;
+                // Logarithmic sweep across the audible range, 20Hz-20kHz.
+                let freq = 20.0 * (1_000.0_f32).powf(progress);
+                self.next_sine_sample(freq)
+            }
+            GenerateArg::Pink => self.next_pink_sample(),
+        };
+
+        self.elapsed_samples += 1;
+        sample
+    }
+
+    fn next_sine_sample(&mut self, freq_hz: f32) -> f32 {
+        let sample = (2.0 * std::f32::consts::PI * self.phase).sin();
+        self.phase = (self.phase + freq_hz / self.sample_rate_hz as f32).fract();
+        sample
+    }
+
+    /// Voss-McCartney pink noise: each sample updates one "row" of white noise, chosen by the
+    /// trailing-zero count of a running counter so rows flip at successive octave rates, then
+    /// sums all rows. Approximates a 1/f power spectrum with a handful of white noise sources.
+    fn next_pink_sample(&mut self) -> f32 {
+        self.pink_counter = self.pink_counter.wrapping_add(1);
+        let row = (self.pink_counter.trailing_zeros() as usize) % self.pink_rows.len();
+        self.pink_rows[row] = self.rng.next_signed_f32();
+
+        self.pink_rows.iter().sum::<f32>() / self.pink_rows.len() as f32
+    }
+}
+
+/// A small, dependency-free xorshift PRNG; good enough for synthesizing pink noise.
+struct Xorshift32(u32);
+
+impl Xorshift32 {
+    fn new(seed: u32) -> Xorshift32 {
+        Xorshift32(seed)
+    }
+
+    fn next_signed_f32(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+/// Synthesizes audio with [`Siggen`] and feeds it through the analysis pipeline in
+/// `frame_samples`-sized hops at the real-time frame rate, forever.
+fn run_generate_source(
+    mode: GenerateArg,
+    frame_samples: usize,
+    sample_rate_hz: u32,
+    gen_freq_hz: f32,
+    gen_sweep_secs: f32,
+    mut processor: FrameProcessor,
+) {
+    let mut siggen = Siggen::new(mode, sample_rate_hz, gen_freq_hz, gen_sweep_secs);
+    let hop_duration = Duration::from_secs_f64(frame_samples as f64 / sample_rate_hz as f64);
 
     loop {
-        thread::sleep(Duration::from_secs(1))
+        let hop_start = Instant::now();
+        let hop = siggen.next_hop(frame_samples);
+        processor.process(&hop);
+
+        let elapsed = hop_start.elapsed();
+        if elapsed < hop_duration {
+            thread::sleep(hop_duration - elapsed);
+        }
     }
 }
 
+/// Decodes `path` with symphonia, downmixes to mono, resamples to `sample_rate_hz` with rubato,
+/// then feeds the existing analysis pipeline in `frame_samples`-sized hops, sleeping between
+/// hops so playback runs at real-time speed. Repeats forever if `loop_playback` is set.
+fn run_file_source(
+    path: &Path,
+    loop_playback: bool,
+    frame_samples: usize,
+    sample_rate_hz: u32,
+    mut processor: FrameProcessor,
+) {
+    let hop_duration = Duration::from_secs_f64(frame_samples as f64 / sample_rate_hz as f64);
+
+    loop {
+        let (mono_samples, native_rate) = decode_to_mono(path);
+        let resampled = resample_to_rate(&mono_samples, native_rate, sample_rate_hz);
+
+        for hop in resampled.chunks(frame_samples) {
+            let hop_start = Instant::now();
+            if hop.len() == frame_samples {
+                processor.process(hop);
+            } else {
+                let mut padded = hop.to_vec();
+                padded.resize(frame_samples, 0.0);
+                processor.process(&padded);
+            }
+
+            let elapsed = hop_start.elapsed();
+            if elapsed < hop_duration {
+                thread::sleep(hop_duration - elapsed);
+            }
+        }
+
+        if !loop_playback {
+            break;
+        }
+    }
+}
+
+/// Decodes every packet in `path` to a single downmixed mono `f32` buffer, returning it along
+/// with the file's native sample rate.
+fn decode_to_mono(path: &Path) -> (Vec<f32>, u32) {
+    let file = std::fs::File::open(path).expect("Couldn't open audio file");
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .expect("Unsupported or unrecognized audio format");
+    let mut format = probed.format;
+
+    let track = format.tracks().iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .expect("No supported audio tracks in file")
+        .clone();
+    let native_rate = track.codec_params.sample_rate.expect("Track has no sample rate");
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .expect("Unsupported codec");
+
+    let mut mono_samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(_)) => break, // EOF
+            Err(err) => panic!("Error reading packet: {}", err),
+        };
+
+        if packet.track_id() != track.id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(decoded) => downmix_to_mono(&decoded, &mut mono_samples),
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => panic!("Error decoding packet: {}", err),
+        }
+    }
+
+    (mono_samples, native_rate)
+}
+
+fn downmix_to_mono(decoded: &AudioBufferRef, out: &mut Vec<f32>) {
+    match decoded {
+        AudioBufferRef::U8(buf) => downmix_planar(buf, out),
+        AudioBufferRef::U16(buf) => downmix_planar(buf, out),
+        AudioBufferRef::U24(buf) => downmix_planar(buf, out),
+        AudioBufferRef::U32(buf) => downmix_planar(buf, out),
+        AudioBufferRef::S8(buf) => downmix_planar(buf, out),
+        AudioBufferRef::S16(buf) => downmix_planar(buf, out),
+        AudioBufferRef::S24(buf) => downmix_planar(buf, out),
+        AudioBufferRef::S32(buf) => downmix_planar(buf, out),
+        AudioBufferRef::F32(buf) => downmix_planar(buf, out),
+        AudioBufferRef::F64(buf) => downmix_planar(buf, out),
+    }
+}
+
+fn downmix_planar<S>(buf: &AudioBuffer<S>, out: &mut Vec<f32>)
+where
+    S: Sample + IntoSample<f32>,
+{
+    let channels = buf.spec().channels.count();
+    for frame in 0..buf.frames() {
+        let sum: f32 = (0..channels).map(|ch| buf.chan(ch)[frame].into_sample()).sum();
+        out.push(sum / channels as f32);
+    }
+}
+
+/// Resamples `samples` from `from_rate` to `to_rate` with rubato, processing in fixed-size
+/// chunks. A no-op copy when the rates already match.
+fn resample_to_rate(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate {
+        return samples.to_vec();
+    }
+
+    const CHUNK_SIZE: usize = 1024;
+    let mut resampler = rubato::FftFixedIn::<f32>::new(
+        from_rate as usize,
+        to_rate as usize,
+        CHUNK_SIZE,
+        2,
+        1,
+    ).expect("Couldn't build resampler");
+
+    let mut output = Vec::new();
+    for chunk in samples.chunks(CHUNK_SIZE) {
+        let mut padded = chunk.to_vec();
+        padded.resize(CHUNK_SIZE, 0.0);
+        let resampled = resampler.process(&[padded], None).expect("Resample failed");
+        output.extend_from_slice(&resampled[0]);
+    }
+
+    // The last chunk was zero-padded to CHUNK_SIZE before resampling, which appends trailing
+    // silence; trim back to the length the input actually resamples to.
+    let expected_len = (samples.len() as f64 * to_rate as f64 / from_rate as f64).ceil() as usize;
+    output.truncate(expected_len.min(output.len()));
+
+    output
+}
+
 trait Shortable {
     fn to_scaled_u16(self) -> u16;
 }
@@ -199,4 +639,4 @@ impl Shortable for f32 {
     fn to_scaled_u16(self) -> u16 {
         (self * u16::MAX as f32) as u16
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,15 @@
-use std::collections::HashSet;
-use std::io::{Cursor, Write};
+use std::collections::{HashSet, VecDeque};
+use std::io::{Cursor, Read, Write};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use std::io::Result;
 
+/// Packet type identifying an SB10 discovery/sensor frame.
+const SB10_PACKET_TYPE: i32 = 50;
+
+/// Expansion board type byte identifying an SB10 sensor board.
+const SB10_EXPANSION_TYPE: u8 = 1;
+
 const SENSOR_DATA_BYTES: usize = 32 * 2 + //Frequency buckets
     2 + // Energy average
     2 + // maxFreqMagnitude
@@ -28,19 +34,39 @@ pub struct AudioData {
     pub max_freq: u16,
 }
 
+/// The outcome of sending one frame to one target.
+pub struct SendOutcome {
+    pub target: SocketAddr,
+    pub result: Result<()>,
+}
+
+/// The outcome of a single `send_frame` call. `sent` is `false` when a frame-rate cap
+/// suppressed the send entirely, in which case `outcomes` is empty.
+pub struct SendSummary {
+    pub sent: bool,
+    pub outcomes: Vec<SendOutcome>,
+}
+
 pub struct SensorClient {
     sender_id: [u8; 4],
     targets: HashSet<SocketAddr>,
     frame_type: [u8; 4],
+    socket: UdpSocket,
+    min_frame_interval: Option<Duration>,
+    last_send: Option<Instant>,
 }
 
 impl SensorClient {
-    pub fn new(sender_id: u32) -> SensorClient {
-        SensorClient {
+    pub fn new(sender_id: u32) -> Result<SensorClient> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        Ok(SensorClient {
             sender_id: sender_id.to_le_bytes(),
             targets: HashSet::new(),
-            frame_type: 50_i32.to_le_bytes(),
-        }
+            frame_type: SB10_PACKET_TYPE.to_le_bytes(),
+            socket,
+            min_frame_interval: None,
+            last_send: None,
+        })
     }
 
     pub fn add_target(&mut self, addr: SocketAddr) {
@@ -51,54 +77,411 @@ impl SensorClient {
         self.targets.remove(addr);
     }
 
+    /// Caps how often `send_frame` will put a frame on the wire: calls that arrive sooner
+    /// than `interval` since the last actual send are silently dropped. Pass `None` (the
+    /// default) to send every frame uncapped.
+    pub fn set_min_frame_interval(&mut self, interval: Option<Duration>) {
+        self.min_frame_interval = interval;
+    }
+
     pub fn send_frame(
-        &self,
+        &mut self,
         audio: &AudioData,
         accel: &[i16; 3],
         light: u16,
         analog: &[u16; 5],
-    ) -> Result<()> {
+    ) -> Result<SendSummary> {
+        if let Some(interval) = self.min_frame_interval {
+            if self.last_send.map(|last| last.elapsed() < interval).unwrap_or(false) {
+                return Ok(SendSummary { sent: false, outcomes: Vec::new() });
+            }
+        }
+
         let ts_millis: u32 = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .map(|dur| dur.as_millis().try_into().unwrap_or(u32::MAX))
             .unwrap_or(0);
 
-        let mut cursor = Cursor::new([0; FRAME_BYTES]);
+        let frame = build_frame(
+            &self.frame_type,
+            &self.sender_id,
+            ts_millis,
+            audio,
+            accel,
+            light,
+            analog,
+        )?;
+
+        let outcomes: Vec<SendOutcome> = self.targets.iter()
+            .map(|target| SendOutcome {
+                target: *target,
+                result: self.socket.send_to(&frame, target).map(|_| ()),
+            })
+            .collect();
+
+        self.last_send = Some(Instant::now());
+
+        Ok(SendSummary { sent: true, outcomes })
+    }
+}
+
+fn build_frame(
+    frame_type: &[u8; 4],
+    sender_id: &[u8; 4],
+    ts_millis: u32,
+    audio: &AudioData,
+    accel: &[i16; 3],
+    light: u16,
+    analog: &[u16; 5],
+) -> Result<[u8; FRAME_BYTES]> {
+    let mut cursor = Cursor::new([0; FRAME_BYTES]);
+
+    // Write header
+    cursor.write_all(frame_type)?;
+    cursor.write_all(sender_id)?;
+    cursor.write_all(&ts_millis.to_le_bytes())?;
+    cursor.write_all(&[SB10_EXPANSION_TYPE])?;
+    cursor.set_position(cursor.position() + 3);
+
+    // Audio
+    for bucket in &audio.freq_buckets {
+        cursor.write_all(&bucket.to_le_bytes())?;
+    }
+    cursor.write_all(&audio.energy_avg.to_le_bytes())?;
+    cursor.write_all(&audio.max_freq_magnitude.to_le_bytes())?;
+    cursor.write_all(&audio.max_freq.to_le_bytes())?;
+
+    // Other sensor data
+    for axis in accel {
+        cursor.write_all(&axis.to_le_bytes())?;
+    }
+    cursor.write_all(&light.to_le_bytes())?;
+    for input in analog {
+        cursor.write_all(&input.to_le_bytes())?;
+    }
+
+    assert_eq!(cursor.position(), FRAME_BYTES as u64);
+
+    Ok(cursor.into_inner())
+}
+
+/// A decoded SB10 sensor frame, the inverse of [`SensorClient::send_frame`]'s wire format.
+pub struct SensorFrame {
+    pub sender_id: u32,
+    pub timestamp_millis: u32,
+    pub audio: AudioData,
+    pub accel: [i16; 3],
+    pub light: u16,
+    pub analog: [u16; 5],
+}
+
+fn read_u16(cursor: &mut Cursor<&[u8]>) -> Option<u16> {
+    let mut bytes = [0u8; 2];
+    cursor.read_exact(&mut bytes).ok()?;
+    Some(u16::from_le_bytes(bytes))
+}
+
+fn read_i16(cursor: &mut Cursor<&[u8]>) -> Option<i16> {
+    read_u16(cursor).map(|bits| bits as i16)
+}
+
+/// Parses a raw SB10 frame as produced by [`SensorClient::send_frame`], returning `None` if
+/// the packet type or expansion byte don't identify it as one.
+pub fn parse_frame(frame: &[u8; FRAME_BYTES]) -> Option<SensorFrame> {
+    let mut cursor = Cursor::new(&frame[..]);
+
+    let mut packet_type = [0u8; 4];
+    cursor.read_exact(&mut packet_type).ok()?;
+    if i32::from_le_bytes(packet_type) != SB10_PACKET_TYPE {
+        return None;
+    }
+
+    let mut sender_id = [0u8; 4];
+    cursor.read_exact(&mut sender_id).ok()?;
+
+    let mut ts_bytes = [0u8; 4];
+    cursor.read_exact(&mut ts_bytes).ok()?;
+    let timestamp_millis = u32::from_le_bytes(ts_bytes);
+
+    let mut expansion_type = [0u8; 1];
+    cursor.read_exact(&mut expansion_type).ok()?;
+    if expansion_type[0] != SB10_EXPANSION_TYPE {
+        return None;
+    }
+    cursor.set_position(cursor.position() + 3);
+
+    let mut freq_buckets = [0u16; 32];
+    for bucket in &mut freq_buckets {
+        *bucket = read_u16(&mut cursor)?;
+    }
+    let energy_avg = read_u16(&mut cursor)?;
+    let max_freq_magnitude = read_u16(&mut cursor)?;
+    let max_freq = read_u16(&mut cursor)?;
+
+    let mut accel = [0i16; 3];
+    for axis in &mut accel {
+        *axis = read_i16(&mut cursor)?;
+    }
+    let light = read_u16(&mut cursor)?;
+
+    let mut analog = [0u16; 5];
+    for input in &mut analog {
+        *input = read_u16(&mut cursor)?;
+    }
+
+    Some(SensorFrame {
+        sender_id: u32::from_le_bytes(sender_id),
+        timestamp_millis,
+        audio: AudioData { freq_buckets, energy_avg, max_freq_magnitude, max_freq },
+        accel,
+        light,
+        analog,
+    })
+}
+
+/// A listening counterpart to [`SensorClient`]: binds a socket and decodes incoming SB10
+/// frames, useful as a virtual Pixelblaze for tests or for forwarding/monitoring another
+/// machine's sensor stream. Iterate it to receive decoded frames; packets that don't parse
+/// as SB10 frames are silently skipped.
+pub struct SensorServer {
+    socket: UdpSocket,
+}
+
+impl SensorServer {
+    pub fn bind(addr: SocketAddr) -> Result<SensorServer> {
+        Ok(SensorServer { socket: UdpSocket::bind(addr)? })
+    }
+}
+
+impl Iterator for SensorServer {
+    type Item = Result<SensorFrame>;
+
+    fn next(&mut self) -> Option<Result<SensorFrame>> {
+        loop {
+            // One spare byte so an oversized datagram shows up as n > FRAME_BYTES instead of
+            // being silently truncated to a frame-sized one.
+            let mut buf = [0u8; FRAME_BYTES + 1];
+            match self.socket.recv(&mut buf) {
+                Ok(n) if n == FRAME_BYTES => if let Some(frame) = parse_frame(
+                    buf[..FRAME_BYTES].try_into().expect("slice is FRAME_BYTES long"),
+                ) {
+                    return Some(Ok(frame));
+                },
+                Ok(_) => {} // Short/oversized datagram, not a valid SB10 frame
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
 
-        // Write header
-        cursor.write_all(&self.frame_type)?;
-        cursor.write_all(&self.sender_id)?;
-        cursor.write_all(&ts_millis.to_le_bytes())?;
-        cursor.write_all(&[1_u8])?; // It's an SB10 sensor board
-        cursor.set_position(cursor.position() + 3);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Audio
-        for bucket in &audio.freq_buckets {
-            cursor.write_all(&bucket.to_le_bytes())?;
+    fn sample_audio() -> AudioData {
+        let mut freq_buckets = [0u16; 32];
+        for (i, bucket) in freq_buckets.iter_mut().enumerate() {
+            *bucket = (i as u16) * 100;
         }
-        cursor.write_all(&audio.energy_avg.to_le_bytes())?;
-        cursor.write_all(&audio.max_freq_magnitude.to_le_bytes())?;
-        cursor.write_all(&audio.max_freq.to_le_bytes())?;
 
-        // Other sensor data
-        for axis in accel {
-            cursor.write_all(&axis.to_le_bytes())?;
+        AudioData {
+            freq_buckets,
+            energy_avg: 1234,
+            max_freq_magnitude: 5678,
+            max_freq: 9012,
+        }
+    }
+
+    #[test]
+    fn round_trips_a_frame() {
+        let frame_type = SB10_PACKET_TYPE.to_le_bytes();
+        let sender_id = 42_u32.to_le_bytes();
+        let accel = [-100_i16, 0, 3200];
+        let light = 42;
+        let analog = [1, 2, 3, 4, 5];
+
+        let bytes = build_frame(&frame_type, &sender_id, 123_456, &sample_audio(), &accel, light, &analog)
+            .expect("build_frame failed");
+
+        let parsed = parse_frame(&bytes).expect("Frame should parse");
+
+        assert_eq!(parsed.sender_id, 42);
+        assert_eq!(parsed.timestamp_millis, 123_456);
+        assert_eq!(parsed.audio.freq_buckets, sample_audio().freq_buckets);
+        assert_eq!(parsed.audio.energy_avg, 1234);
+        assert_eq!(parsed.audio.max_freq_magnitude, 5678);
+        assert_eq!(parsed.audio.max_freq, 9012);
+        assert_eq!(parsed.accel, accel);
+        assert_eq!(parsed.light, light);
+        assert_eq!(parsed.analog, analog);
+    }
+
+    #[test]
+    fn rejects_wrong_packet_type() {
+        let frame_type = 51_i32.to_le_bytes();
+        let sender_id = 1_u32.to_le_bytes();
+
+        let bytes = build_frame(&frame_type, &sender_id, 0, &sample_audio(), &[0; 3], 0, &[0; 5])
+            .expect("build_frame failed");
+
+        assert!(parse_frame(&bytes).is_none());
+    }
+}
+
+/// Roughly one second of history at a ~43fps analysis rate, matching common FFT hop sizes.
+const BEAT_FLUX_WINDOW_FRAMES: usize = 43;
+
+/// Beats within this long of the last one are ignored, since onsets repeat in quick bursts.
+const BEAT_REFRACTORY: Duration = Duration::from_millis(120);
+
+/// How much the beat envelope decays each frame once it's not actively triggered.
+const BEAT_ENVELOPE_DECAY: f32 = 0.85;
+
+/// Spectral-flux onset (beat) detector.
+///
+/// Feed it the magnitude spectrum of each successive audio frame via [`BeatDetector::process`]
+/// and it returns a beat intensity in `0.0..=1.0`: it jumps to `1.0` the instant a beat is
+/// detected and decays smoothly afterward, so patterns driven by it get a pulse rather than a
+/// single-frame blip. All buffers are preallocated so steady-state calls do no allocation.
+pub struct BeatDetector {
+    sensitivity: f32,
+    prev_magnitudes: Vec<f32>,
+    prev_flux: f32,
+    flux_window: VecDeque<f32>,
+    envelope: f32,
+    last_beat: Option<Instant>,
+}
+
+impl BeatDetector {
+    /// `sensitivity` multiplies the rolling average flux to form the onset threshold; higher
+    /// values require a bigger jump in energy to register as a beat. ~1.3-1.8 is a good range.
+    pub fn new(sensitivity: f32) -> BeatDetector {
+        BeatDetector {
+            sensitivity,
+            prev_magnitudes: Vec::new(),
+            prev_flux: 0.0,
+            flux_window: VecDeque::with_capacity(BEAT_FLUX_WINDOW_FRAMES),
+            envelope: 0.0,
+            last_beat: None,
         }
-        cursor.write_all(&light.to_le_bytes())?;
-        for input in analog {
-            cursor.write_all(&input.to_le_bytes())?;
+    }
+
+    /// Consume one frame's magnitude spectrum and return the current beat envelope.
+    pub fn process(&mut self, magnitudes: &[f32]) -> f32 {
+        if self.prev_magnitudes.len() != magnitudes.len() {
+            self.prev_magnitudes.clear();
+            self.prev_magnitudes.resize(magnitudes.len(), 0.0);
         }
 
-        assert_eq!(cursor.position(), FRAME_BYTES as u64);
+        let flux: f32 = magnitudes.iter()
+            .zip(self.prev_magnitudes.iter())
+            .map(|(mag, prev)| (mag - prev).max(0.0))
+            .sum();
+        self.prev_magnitudes.copy_from_slice(magnitudes);
+
+        let threshold = if self.flux_window.is_empty() {
+            f32::MAX
+        } else {
+            let avg = self.flux_window.iter().sum::<f32>() / self.flux_window.len() as f32;
+            avg * self.sensitivity
+        };
 
-        let frame = cursor.into_inner();
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let is_local_max = flux > self.prev_flux;
+        let past_refractory = self.last_beat
+            .map(|last| last.elapsed() >= BEAT_REFRACTORY)
+            .unwrap_or(true);
 
-        for target in &self.targets {
-            socket.connect(target)?;
-            socket.send(&frame)?;
+        if flux > threshold && is_local_max && past_refractory {
+            self.envelope = 1.0;
+            self.last_beat = Some(Instant::now());
+        } else {
+            self.envelope *= BEAT_ENVELOPE_DECAY;
         }
 
-        Ok(())
+        if self.flux_window.len() == BEAT_FLUX_WINDOW_FRAMES {
+            self.flux_window.pop_front();
+        }
+        self.flux_window.push_back(flux);
+        self.prev_flux = flux;
+
+        self.envelope
+    }
+}
+
+/// How to spread the bucket edges across a frequency range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BucketScale {
+    /// Equal-width buckets in Hz.
+    Linear,
+    /// Equal-width buckets in log-frequency, so low buckets are narrow and high ones wide.
+    Log,
+    /// Equal-width buckets on the mel scale, approximating perceived pitch spacing.
+    Mel,
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Maps an FFT magnitude spectrum into a fixed number of frequency buckets. The `[start, end)`
+/// bin range feeding each bucket is precomputed once at construction, so bucketing a frame is
+/// just summing precomputed slices.
+pub struct FrequencyBucketer {
+    bin_ranges: Vec<(usize, usize)>,
+}
+
+impl FrequencyBucketer {
+    /// `bin_count` is the number of magnitude bins in each frame's spectrum (FFT length / 2)
+    /// and `sample_rate_hz` its sample rate; `[low_hz, high_hz)` is spread across
+    /// `bucket_count` buckets according to `scale`.
+    pub fn new(
+        scale: BucketScale,
+        low_hz: f32,
+        high_hz: f32,
+        bucket_count: usize,
+        bin_count: usize,
+        sample_rate_hz: u32,
+    ) -> FrequencyBucketer {
+        let bin_hz = sample_rate_hz as f32 / 2.0 / bin_count as f32;
+
+        let edges: Vec<f32> = match scale {
+            BucketScale::Linear => (0..=bucket_count)
+                .map(|i| low_hz + (high_hz - low_hz) * (i as f32 / bucket_count as f32))
+                .collect(),
+            BucketScale::Log => {
+                let (log_low, log_high) = (low_hz.max(1.0).ln(), high_hz.max(1.0).ln());
+                (0..=bucket_count)
+                    .map(|i| (log_low + (log_high - log_low) * (i as f32 / bucket_count as f32)).exp())
+                    .collect()
+            }
+            BucketScale::Mel => {
+                let (mel_low, mel_high) = (hz_to_mel(low_hz), hz_to_mel(high_hz));
+                (0..=bucket_count)
+                    .map(|i| mel_to_hz(mel_low + (mel_high - mel_low) * (i as f32 / bucket_count as f32)))
+                    .collect()
+            }
+        };
+
+        let bin_ranges = edges.windows(2)
+            .map(|edge_pair| {
+                let start = ((edge_pair[0] / bin_hz).floor() as usize).min(bin_count);
+                let end = ((edge_pair[1] / bin_hz).ceil() as usize).clamp(start, bin_count);
+                (start, end)
+            })
+            .collect();
+
+        FrequencyBucketer { bin_ranges }
+    }
+
+    /// Sums the magnitudes falling in each bucket's precomputed bin range. `magnitudes` must
+    /// cover (at least) the `bin_count` this bucketer was built with.
+    pub fn bucket(&self, magnitudes: &[f32]) -> Vec<f32> {
+        self.bin_ranges.iter()
+            .map(|&(start, end)| magnitudes[start..end.min(magnitudes.len())].iter().sum())
+            .collect()
     }
 }
\ No newline at end of file